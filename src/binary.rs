@@ -0,0 +1,208 @@
+// Copyright (c) 2021, Andrey Dubovik <andrei@dubovik.eu>
+
+// A compact, self-describing binary encoding for `Wiktionary`.
+//
+// The grammar is a flat sequence of varint-prefixed fields in a fixed
+// order (source, license, words, pos, plural_of, alt_forms). Integer
+// sets (the pos sets and relation tables) are delta-encoded with zigzag
+// varints, sorted first wherever their order carries no meaning (cluster
+// members keep their stored order), since ids are dense and the deltas
+// are small; this is what
+// buys the order-of-magnitude size reduction over JSON on a full dump.
+// Decoding reconstructs a `Wiktionary` that is identical to the one
+// that was encoded, so JSON and binary output are interchangeable:
+// encode(w) and serde_json::to_string(w) describe the same value, and
+// decode(&encode(w)) == w.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{Relations, Wiktionary};
+
+const MAGIC: &[u8; 4] = b"WKTB";
+const VERSION: u8 = 1;
+
+// Unsigned LEB128 varint
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+// Zigzag encoding maps signed deltas onto unsigned varints efficiently
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_string<'a>(bytes: &'a [u8], pos: &mut usize) -> String {
+    let len = read_varint(bytes, pos) as usize;
+    let s = std::str::from_utf8(&bytes[*pos..*pos + len]).unwrap().to_string();
+    *pos += len;
+    s
+}
+
+// Write a slice of ids as a varint count followed by zigzag delta varints.
+// Deltas need not be positive, but this is most compact for sorted ids
+fn write_id_list(buf: &mut Vec<u8>, ids: &[usize]) {
+    write_varint(buf, ids.len() as u64);
+    let mut prev = 0i64;
+    for &id in ids {
+        write_varint(buf, zigzag_encode(id as i64 - prev));
+        prev = id as i64;
+    }
+}
+
+fn read_id_list(bytes: &[u8], pos: &mut usize) -> Vec<usize> {
+    let count = read_varint(bytes, pos) as usize;
+    let mut ids = Vec::with_capacity(count);
+    let mut prev = 0i64;
+    for _ in 0..count {
+        prev += zigzag_decode(read_varint(bytes, pos));
+        ids.push(prev as usize);
+    }
+    ids
+}
+
+pub fn encode(wiktionary: &Wiktionary) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+
+    write_string(&mut buf, &wiktionary.source);
+    write_string(&mut buf, &wiktionary.license);
+
+    write_varint(&mut buf, wiktionary.words.len() as u64);
+    for word in &wiktionary.words {
+        write_string(&mut buf, word);
+    }
+
+    let mut pos_keys: Vec<&String> = wiktionary.pos.keys().collect();
+    pos_keys.sort();
+    write_varint(&mut buf, pos_keys.len() as u64);
+    for key in pos_keys {
+        write_string(&mut buf, key);
+        let mut ids: Vec<usize> = wiktionary.pos[key].iter().copied().collect();
+        ids.sort_unstable();
+        write_id_list(&mut buf, &ids);
+    }
+
+    let mut edges: Vec<(usize, usize)> = wiktionary.rel.plural_of.iter().copied().collect();
+    edges.sort_unstable();
+    write_varint(&mut buf, edges.len() as u64);
+    let mut prev = 0i64;
+    for (i, j) in edges {
+        write_varint(&mut buf, zigzag_encode(i as i64 - prev));
+        write_varint(&mut buf, zigzag_encode(j as i64 - i as i64));
+        prev = i as i64;
+    }
+
+    // Clusters are keyed by their first-inserted member (see `Partitioner`),
+    // not their minimum one, and member order within a cluster is meaningful
+    // (it's compared by `==`), so each cluster's member order is preserved
+    // here exactly as stored, rather than sorted; the clusters themselves
+    // are written in order of their key (first member)
+    let mut clusters: Vec<&Vec<usize>> = wiktionary.rel.alt_forms.values().collect();
+    clusters.sort_unstable_by_key(|members| members[0]);
+    write_varint(&mut buf, clusters.len() as u64);
+    for members in clusters {
+        write_id_list(&mut buf, members);
+    }
+
+    buf
+}
+
+pub fn decode(bytes: &[u8]) -> Wiktionary {
+    assert_eq!(&bytes[0..4], MAGIC, "not a Wiktionary binary stream");
+    assert_eq!(bytes[4], VERSION, "unsupported Wiktionary binary version");
+    let mut pos = 5;
+
+    let source = read_string(bytes, &mut pos);
+    let license = read_string(bytes, &mut pos);
+
+    let word_count = read_varint(bytes, &mut pos) as usize;
+    let mut words = Vec::with_capacity(word_count);
+    for _ in 0..word_count {
+        words.push(read_string(bytes, &mut pos));
+    }
+
+    let pos_count = read_varint(bytes, &mut pos) as usize;
+    let mut pos_map = HashMap::with_capacity(pos_count);
+    for _ in 0..pos_count {
+        let key = read_string(bytes, &mut pos);
+        let ids: HashSet<usize> = read_id_list(bytes, &mut pos).into_iter().collect();
+        pos_map.insert(key, ids);
+    }
+
+    let edge_count = read_varint(bytes, &mut pos) as usize;
+    let mut plural_of = HashSet::with_capacity(edge_count);
+    let mut prev = 0i64;
+    for _ in 0..edge_count {
+        let i = prev + zigzag_decode(read_varint(bytes, &mut pos));
+        let j = i + zigzag_decode(read_varint(bytes, &mut pos));
+        plural_of.insert((i as usize, j as usize));
+        prev = i;
+    }
+
+    let cluster_count = read_varint(bytes, &mut pos) as usize;
+    let mut alt_forms = HashMap::with_capacity(cluster_count);
+    for _ in 0..cluster_count {
+        let members = read_id_list(bytes, &mut pos);
+        alt_forms.insert(members[0], members);
+    }
+
+    Wiktionary {
+        source,
+        license,
+        words,
+        pos: pos_map,
+        rel: Relations { plural_of, alt_forms },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut wiktionary = Wiktionary::new();
+        wiktionary.words = vec!["cat".into(), "cats".into(), "kitty".into(), "kitten".into()];
+        wiktionary.pos.insert("noun".into(), [0, 1, 2].iter().copied().collect());
+        wiktionary.rel.plural_of.insert((0, 1));
+        // Keyed on 3 (first-inserted), whose only other member, 2, is smaller:
+        // exercises the case a min-keyed encoding would get wrong
+        wiktionary.rel.alt_forms.insert(3, vec![3, 2]);
+
+        assert_eq!(decode(&encode(&wiktionary)), wiktionary);
+    }
+}
@@ -1,13 +1,16 @@
 // Copyright (c) 2021, Andrey Dubovik <andrei@dubovik.eu>
 
 // Standard library
+use std::borrow::Cow;
 use std::collections::{HashSet, HashMap, BTreeMap};
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read, Write};
 use std::iter;
+use std::sync::mpsc;
+use std::thread;
 
 // Crates
 use lazy_static::lazy_static;
-use phf::{phf_set, phf_map};
+use phf::phf_set;
 use quick_xml::Reader;
 use quick_xml::events::Event;
 use regex::Regex;
@@ -16,9 +19,12 @@ use serde::ser::{Serializer, SerializeSeq};
 use serde_json;
 
 // Local modules
+mod binary;
 mod partitioner;
+mod rules;
 mod template;
 use partitioner::Partitioner;
+use rules::{CompiledConfig, CompiledRule, RelationKind};
 
 
 // Create an iterator over wiktionary pages
@@ -69,49 +75,86 @@ macro_rules! lazy_regex {
 }
 
 lazy_regex! {
-    ENGLISH: r"(?ism)(?:^== *english *== *\n)(.*?)(?:^={1,2}[^=]|\z)",
-    SECTION: r"(?m)^=+ *([^=]+?)( [0-9]+)? *=+ *\n",
-    OBSOLETE: r"\b(?:obsolete|archaic|dated|rare)\b",
+    SECTION: r"(?m)^(=+) *([^=\n]+?) *=+ *\n",
 }
 
 
-// Find the English block, if any
-fn extract_english(text: &str) -> Option<&str> {
-    ENGLISH.captures(text).map(|c| c.get(1).unwrap().as_str())
+// Find the configured language's block, if any
+fn extract_section<'a>(text: &'a str, header: &Regex) -> Option<&'a str> {
+    header.captures(text).map(|c| c.get(1).unwrap().as_str())
 }
 
 
-// Create a flat iterator over Markdown sections
-fn sections(text: &str) -> impl Iterator<Item = (Option<String>, &str)> {
-    let mut cur = 0;
+// A homograph or etymology counter (e.g. the "2" in "Etymology 2" or "Noun 2")
+// disambiguates sibling headings but is not part of the heading's kind, so
+// `POS_HEADERS` and similar lookups should ignore it
+fn strip_ordinal(title: &str) -> &str {
+    match title.rfind(' ') {
+        Some(i) if !title[i+1..].is_empty() && title[i+1..].bytes().all(|b| b.is_ascii_digit()) => &title[..i],
+        _ => title,
+    }
+}
+
+
+// One node of the section tree: `title` is this heading's kind (ordinal
+// stripped, e.g. "noun"), `path` is the full ancestor chain down to and
+// including this heading (ordinals kept, e.g. ["etymology 1", "noun"]), and
+// `text` is the body text that belongs directly to this heading
+struct Section<'a> {
+    title: Option<String>,
+    path: Vec<String>,
+    text: &'a str,
+}
+
+
+// Build the section tree: headings are nested by `=` depth, and each run of
+// body text is attached to the deepest heading open at that point
+fn sections(text: &str) -> Vec<Section> {
+    let mut out = Vec::new();
+    let mut stack: Vec<(usize, String)> = Vec::new();  // (level, full title) ancestor chain
     let mut title = None;
+    let mut cur = 0;
     let mut captures = SECTION.capture_locations();
-    iter::from_fn(move || {
-        if cur < text.len() {
-            let (title, content) = match SECTION.captures_read_at(&mut captures, &text, cur) {
-                Some(m) => {
-                    let ptitle = title;
-                    title = Some(captures.get(1).unwrap());
-                    let content = &text[cur..m.start()];
-                    cur = m.end();
-                    (ptitle, content)
-                },
-                None => {
-                    let content = &text[cur..];
-                    cur = text.len();
-                    (title, content)
-                },
-            };
-            let title = title.map(|(i, j)| {
-                let mut title = String::from(&text[i..j]);
-                title.make_ascii_lowercase();  // Proper Noun == Proper noun
-                title
-            });
-            Some((title, content))
-        } else {
-            None
+    loop {
+        match SECTION.captures_read_at(&mut captures, text, cur) {
+            Some(m) => {
+                let body = &text[cur..m.start()];
+                out.push(Section {
+                    title: title.clone(),
+                    path: stack.iter().map(|(_, t)| t.clone()).collect(),
+                    text: body,
+                });
+                let (li, lj) = captures.get(1).unwrap();
+                let level = lj - li;
+                let (ti, tj) = captures.get(2).unwrap();
+                let mut full = String::from(&text[ti..tj]);
+                full.make_ascii_lowercase();  // Proper Noun == Proper noun
+                while matches!(stack.last(), Some((l, _)) if *l >= level) {
+                    stack.pop();
+                }
+                title = Some(strip_ordinal(&full).to_string());
+                stack.push((level, full));
+                cur = m.end();
+            },
+            None => {
+                out.push(Section {
+                    title: title.clone(),
+                    path: stack.iter().map(|(_, t)| t.clone()).collect(),
+                    text: &text[cur..],
+                });
+                break;
+            },
         }
-    })
+    }
+    out
+}
+
+
+// The nearest enclosing part-of-speech heading, ordinals stripped, searching
+// from the innermost ancestor outwards (so a template several subsections
+// deep inside e.g. "Noun" is still recognized as belonging to it)
+fn nearest_pos<'a>(path: &'a [String]) -> Option<&'a str> {
+    path.iter().rev().map(|t| strip_ordinal(t)).find(|t| POS_HEADERS.contains(*t))
 }
 
 
@@ -123,10 +166,14 @@ struct IdTable<'a> {
 
 
 impl<'a> IdTable<'a> {
+    // `vec` may already hold words (e.g. from an earlier shard merge), so the
+    // hash is seeded from it rather than assumed empty; otherwise `get` would
+    // fail to find an existing word and append a duplicate entry for it
     fn new(vec: &'a mut Vec<String>) -> Self {
+        let hash = vec.iter().cloned().enumerate().map(|(i, word)| (word, i)).collect();
         IdTable {
             vec,
-            hash: Default::default(),
+            hash,
         }
     }
 
@@ -141,6 +188,13 @@ impl<'a> IdTable<'a> {
             },
         }
     }
+
+    // Remap a foreign id space into this one: `words[i]` is assumed to be
+    // the word that a foreign id `i` refers to, and the returned table maps
+    // each foreign id to this table's id for the same word
+    fn merge(&mut self, words: &[String]) -> Vec<usize> {
+        words.iter().map(|word| self.get(word)).collect()
+    }
 }
 
 
@@ -157,19 +211,30 @@ fn serialize_values<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok
 }
 
 
+// Deserialize a list of clusters back into a HashMap keyed by each cluster's first member
+// (the inverse of `serialize_values`; this relies on the invariant, upheld by `Partitioner`,
+// that a cluster's key is always its first-inserted member, i.e. `members[0]`)
+fn deserialize_values<'de, D>(deserializer: D) -> Result<HashMap<usize, Vec<usize>>, D::Error>
+    where D: serde::Deserializer<'de>,
+{
+    let clusters: Vec<Vec<usize>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(clusters.into_iter().map(|members| (members[0], members)).collect())
+}
+
+
 // Structures to hold and serialize parsed Wiktionary data
-#[derive(Default, Serialize)]
+#[derive(Default, Debug, PartialEq, Serialize, serde::Deserialize)]
 struct Relations {
     plural_of: HashSet<(usize, usize)>,  // directed edges
-    #[serde(serialize_with = "serialize_values")]
+    #[serde(serialize_with = "serialize_values", deserialize_with = "deserialize_values")]
     alt_forms: HashMap<usize, Vec<usize>>, // clusters
 }
 
 
-#[derive(Default, Serialize)]
+#[derive(Default, Debug, PartialEq, Serialize, serde::Deserialize)]
 struct Wiktionary {
-    source: &'static str,
-    license: &'static str,
+    source: String,
+    license: String,
     words: Vec<String>,
     pos: HashMap<String, HashSet<usize>>,
     rel: Relations,
@@ -179,8 +244,8 @@ struct Wiktionary {
 impl Wiktionary {
     fn new() -> Self {
         Wiktionary {
-            source: &"https://en.wiktionary.org",
-            license: &"https://creativecommons.org/licenses/by-sa/3.0/",
+            source: "https://en.wiktionary.org".into(),
+            license: "https://creativecommons.org/licenses/by-sa/3.0/".into(),
             ..Default::default()
         }
     }
@@ -219,74 +284,75 @@ enum Error {
 
 struct TemplateContext<'a> {
     word: &'a str,
-    section: Option<&'a str>,
-    args: Vec<&'a str>,
-    kwargs: HashMap<&'a str, &'a str>,
+    path: &'a [String],
+    args: Vec<Cow<'a, str>>,
+    kwargs: HashMap<&'a str, Cow<'a, str>>,
 }
 
 
-fn plural_of(view: &mut WiktionaryView, cxt: TemplateContext) -> Result<(), Error> {
-    if let Some("noun") = cxt.section {
-        if cxt.args.get(0).ok_or(Error::MissingTemplateArgument)? == &"en" {
-            let id1 = view.word_id(cxt.word);
-            let id2 = view.word_id(cxt.args.get(1).ok_or(Error::MissingTemplateArgument)?);
-            view.plural_of.insert((id1, id2));
+// Apply one configured extraction rule to a template call, driving the
+// dispatch that used to be hardcoded into `plural_of`/`alt_forms`/`alter`
+fn apply_rule(view: &mut WiktionaryView, rule: &CompiledRule, language_tag: &str, cxt: TemplateContext) -> Result<(), Error> {
+    if let Some(pos) = &rule.pos {
+        if nearest_pos(cxt.path) != Some(pos.as_str()) {
+            return Ok(());
         }
     }
-    Ok(())
-}
-
 
-fn alt_forms(view: &mut WiktionaryView, cxt: TemplateContext) -> Result<(), Error> {
-    if cxt.args.get(0).ok_or(Error::MissingTemplateArgument)? == &"en" {
-        let id1 = view.word_id(cxt.word);
-        let id2 = view.word_id(cxt.args.get(1).ok_or(Error::MissingTemplateArgument)?);
-        view.alt_forms.insert(id1, id2);
+    let language = rules::resolve_arg(&rule.language_arg, &cxt.args, &cxt.kwargs)
+        .ok_or(Error::MissingTemplateArgument)?;
+    if language.as_ref() != language_tag {
+        return Ok(());
     }
-    Ok(())
-}
 
+    // With a delimiter configured (as for `{{alter}}`), every positional
+    // argument up to the delimiter is a target and every one past it is a
+    // dialect/qualifier tag, checked against `skip_if` instead of a target
+    let targets: Vec<Cow<str>> = match &rule.delimiter {
+        Some(delimiter) => {
+            let delim = cxt.args.iter().position(|a| a.as_ref() == delimiter.as_str());
+            let (forms, dialects) = match delim {
+                Some(delim) => (&cxt.args[1..delim], &cxt.args[delim+1..]),
+                None => (&cxt.args[1..], &cxt.args[0..0]),
+            };
+            if let Some(skip_if) = &rule.skip_if {
+                if dialects.iter().any(|arg| skip_if.is_match(arg)) {
+                    return Ok(());
+                }
+            }
+            forms.to_vec()
+        },
+        None => {
+            let targets: Vec<Cow<str>> = rule.word_args.iter()
+                .filter_map(|aref| rules::resolve_arg(aref, &cxt.args, &cxt.kwargs))
+                .collect();
+            if let Some(skip_if) = &rule.skip_if {
+                if targets.iter().any(|arg| skip_if.is_match(arg)) {
+                    return Ok(());
+                }
+            }
+            targets
+        },
+    };
 
-fn alter(view: &mut WiktionaryView, cxt: TemplateContext) -> Result<(), Error> {
-    if cxt.args.get(0).ok_or(Error::MissingTemplateArgument)? == &"en" {
-        let (forms, dialects) = match cxt.args.iter().position(|a| *a == "") {
-            Some(delim) => {
-                (&cxt.args[1..delim], &cxt.args[delim+1..])
-            },
-            None => {
-                (&cxt.args[1..], &cxt.args[0..0])
-            },
-        };
-        // Skip obsolete and rare alternative forms
-        if dialects.iter().any(|arg| OBSOLETE.is_match(arg)) {
-            return Ok(())
-        }
-        let id1 = view.word_id(cxt.word);
-        for arg in forms {
-            let id2 = view.word_id(arg);
-            view.alt_forms.insert(id1, id2);
-        }
+    match rule.relation {
+        RelationKind::PluralOf => {
+            let id1 = view.word_id(cxt.word);
+            let id2 = view.word_id(targets.first().ok_or(Error::MissingTemplateArgument)?);
+            view.plural_of.insert((id1, id2));
+        },
+        RelationKind::AltForms => {
+            let id1 = view.word_id(cxt.word);
+            for target in &targets {
+                let id2 = view.word_id(target);
+                view.alt_forms.insert(id1, id2);
+            }
+        },
     }
     Ok(())
 }
 
 
-// Template dispatching
-static DISPATCHER: phf::Map<&'static str, fn(&mut WiktionaryView, TemplateContext) -> Result<(), Error>> = phf_map! {
-    "plural of" => plural_of,
-    "standard spelling of" => alt_forms,
-    "alternative spelling of" => alt_forms,
-    "standard form of" => alt_forms,
-    "alternative form of" => alt_forms,
-    "stand sp" => alt_forms,
-    "alt sp" => alt_forms,
-    "alt spelling" => alt_forms,
-    "alt form" => alt_forms,
-    "altform" => alt_forms,
-    "alter" => alter,
-};
-
-
 // Explicitly list which parts of speech to collect
 static POS_HEADERS: phf::Set<&'static str> = phf_set! {
     "noun",
@@ -304,32 +370,33 @@ static POS_HEADERS: phf::Set<&'static str> = phf_set! {
 };
 
 
-// Collect specific wiktionary data
-fn collect(reader: impl BufRead) -> Wiktionary {
+// Collect wiktionary data out of a single stream of (already decided) pages;
+// this is the unit of work handed to each worker thread in `collect`
+fn collect_shard(pages: impl Iterator<Item = (String, String)>, config: &CompiledConfig) -> Wiktionary {
     let mut wiktionary = Wiktionary::new();
     let mut view = WiktionaryView::new(&mut wiktionary);
 
-    let reader = pages(reader);
-    for (word, text) in reader {
+    for (word, text) in pages {
         if word.ends_with("/translations") { continue; }
-        if let Some(text) = extract_english(&text) {
-            for (section, text) in sections(text) {
+        if let Some(text) = extract_section(&text, &config.language_header) {
+            for Section { title, path, text } in sections(text) {
                 // Templates
-                template::process_templates(text, |name, args| {
-                    if let Some(func) = DISPATCHER.get(name) {
-                        let (args, kwargs) = template::decode_arguments(args);
-                        func(&mut view, TemplateContext {
+                let buf = template::process_templates(text);
+                for handle in buf.templates() {
+                    if let Some(rule) = config.templates.get(handle.name()) {
+                        let (args, kwargs) = template::decode_arguments(handle.args());
+                        apply_rule(&mut view, rule, &config.language_tag, TemplateContext {
                             word: &word,
-                            section: section.as_deref(),
+                            path: &path,
                             args, kwargs
                         }).ok();
                     }
-                });
+                }
                 // Parts of speech
-                if let Some(section) = section {
-                    if POS_HEADERS.contains(&section) {
+                if let Some(title) = title {
+                    if POS_HEADERS.contains(&title) {
                         let id = view.word_id(&word);
-                        let pos = view.pos.entry(section).or_default();
+                        let pos = view.pos.entry(title).or_default();
                         pos.insert(id);
                     }
                 }
@@ -340,10 +407,137 @@ fn collect(reader: impl BufRead) -> Wiktionary {
 }
 
 
-// stdin -> process -> stdout
+// Fold a shard produced by `collect_shard` into the global `Wiktionary`,
+// remapping the shard's local word ids into the global id space
+fn merge_shard(wiktionary: &mut Wiktionary, shard: Wiktionary) {
+    let mut id_table = IdTable::new(&mut wiktionary.words);
+    let remap = id_table.merge(&shard.words);
+
+    for (key, ids) in shard.pos {
+        let pos = wiktionary.pos.entry(key).or_default();
+        pos.extend(ids.into_iter().map(|i| remap[i]));
+    }
+
+    for (i, j) in shard.rel.plural_of {
+        wiktionary.rel.plural_of.insert((remap[i], remap[j]));
+    }
+
+    let mut alt_forms = Partitioner::new(&mut wiktionary.rel.alt_forms);
+    alt_forms.merge(&shard.rel.alt_forms, &remap);
+}
+
+
+// Bound on how many pages a worker's channel may hold before the producer
+// blocks, so a slow worker pool can't let the whole dump pile up in memory
+// ahead of it (the channel is the only thing standing between this and the
+// streaming baseline's O(1) memory use)
+const CHANNEL_CAPACITY: usize = 16;
+
+
+// Collect specific wiktionary data, optionally spreading the work over
+// `threads` worker threads. Pages are handed out to workers round-robin, in
+// the order `pages()` produces them, and shards are merged back in worker
+// order (0, 1, 2, ...) regardless of which worker finishes first, so the
+// result is reproducible across runs and independent of scheduling
+fn collect(reader: impl BufRead, threads: usize, config: &CompiledConfig) -> Wiktionary {
+    if threads <= 1 {
+        return collect_shard(pages(reader), config);
+    }
+
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        (0..threads).map(|_| mpsc::sync_channel::<(String, String)>(CHANNEL_CAPACITY)).unzip();
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = receivers.into_iter()
+            .map(|rx| scope.spawn(move || collect_shard(rx.into_iter(), config)))
+            .collect();
+
+        for (i, page) in pages(reader).enumerate() {
+            senders[i % threads].send(page).ok();
+        }
+        drop(senders);
+
+        let mut wiktionary = Wiktionary::new();
+        for handle in handles {
+            merge_shard(&mut wiktionary, handle.join().unwrap());
+        }
+        wiktionary
+    })
+}
+
+
+// Output format, selected with `--format json|binary` (defaults to json)
+enum Format {
+    Json,
+    Binary,
+}
+
+
+fn parse_args() -> (Format, Option<Format>, usize, Option<String>) {
+    let mut format = Format::Json;
+    let mut from = None;
+    let mut threads = 1usize;
+    let mut config = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args.next().as_deref() {
+                    Some("json") => Format::Json,
+                    Some("binary") => Format::Binary,
+                    other => panic!("--format expects json or binary, got {:?}", other),
+                };
+            },
+            // Transcode an already-collected dump instead of reading an XML
+            // dump from stdin: read `--from`'s format off stdin and re-emit
+            // it in `--format`'s, e.g. `--from binary --format json`
+            "--from" => {
+                from = Some(match args.next().as_deref() {
+                    Some("json") => Format::Json,
+                    Some("binary") => Format::Binary,
+                    other => panic!("--from expects json or binary, got {:?}", other),
+                });
+            },
+            "--threads" => {
+                threads = args.next()
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .expect("--threads expects a positive integer");
+            },
+            "--config" => {
+                config = Some(args.next().expect("--config expects a path"));
+            },
+            other => panic!("unrecognized argument: {}", other),
+        }
+    }
+    (format, from, threads, config)
+}
+
+
+// stdin -> process -> stdout, or, with `--from`, stdin -> transcode -> stdout
 // TODO: Error handling here and elsewhere
 fn main() {
-    let stdin = io::stdin();
-    let wiktionary = collect(stdin.lock());
-    serde_json::to_writer(io::stdout(), &wiktionary).unwrap();
+    let (format, from, threads, config_path) = parse_args();
+    let wiktionary = match from {
+        Some(from) => {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes).unwrap();
+            match from {
+                Format::Json => serde_json::from_slice(&bytes).unwrap(),
+                Format::Binary => binary::decode(&bytes),
+            }
+        },
+        None => {
+            let config = match config_path {
+                Some(path) => rules::Config::load(&path),
+                None => rules::Config::default_english(),
+            }.compile();
+            let stdin = io::stdin();
+            collect(stdin.lock(), threads, &config)
+        },
+    };
+    match format {
+        Format::Json => serde_json::to_writer(io::stdout(), &wiktionary).unwrap(),
+        Format::Binary => io::stdout().write_all(&binary::encode(&wiktionary)).unwrap(),
+    }
 }
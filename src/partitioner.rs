@@ -13,10 +13,20 @@ pub struct Partitioner<'a> {
 
 
 impl<'a> Partitioner<'a> {
+    // `partitions` may already hold clusters (e.g. from an earlier shard
+    // merge), so the index is rebuilt from it rather than assumed empty;
+    // otherwise `insert` would treat an already-clustered member as new and
+    // corrupt the existing cluster it belongs to
     pub fn new(partitions: &'a mut HashMap<usize, Vec<usize>>) -> Self {
+        let mut index = HashMap::new();
+        for (&key, members) in partitions.iter() {
+            for &member in members {
+                index.insert(member, key);
+            }
+        }
         Partitioner {
             partitions,
-            index: HashMap::new(),
+            index,
         }
     }
 
@@ -62,4 +72,20 @@ impl<'a> Partitioner<'a> {
             },
         }
     }
+
+    // Merge a foreign set of clusters into this partitioning, translating
+    // foreign ids through `remap` (foreign id -> this partitioner's id space).
+    // Clusters are processed in order of their remapped minimum member,
+    // rather than `clusters`' arbitrary HashMap iteration order, so that two
+    // clusters attaching to the same existing partition merge the same way
+    // regardless of scheduling
+    pub fn merge(&mut self, clusters: &HashMap<usize, Vec<usize>>, remap: &[usize]) {
+        let mut clusters: Vec<&Vec<usize>> = clusters.values().collect();
+        clusters.sort_unstable_by_key(|members| members.iter().map(|&m| remap[m]).min());
+        for members in clusters {
+            for pair in members.windows(2) {
+                self.insert(remap[pair[0]], remap[pair[1]]);
+            }
+        }
+    }
 }
@@ -0,0 +1,180 @@
+// Copyright (c) 2021, Andrey Dubovik <andrei@dubovik.eu>
+
+// Data-driven template extraction rules
+//
+// Which templates denote a relation, which language they apply to and how
+// to read the relevant arguments out of them used to be baked into the
+// binary (the `DISPATCHER` map and a handful of hardcoded "en" checks). A
+// `Config` is the same knowledge expressed as data, so it can be loaded
+// from a file at startup instead of requiring a recompile: `Config::load`
+// reads one from disk, `Config::default_english` reproduces the built-in
+// English rule set, and `Config::compile` turns either into a
+// `CompiledConfig` that `collect` drives its dispatching from.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+
+use regex::Regex;
+use serde::Deserialize;
+
+
+// A reference to one of a template's arguments
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgRef {
+    Positional(usize),  // 1-based, as in `{{...|1=...}}` / `{{...|...}}`
+    Named(String),
+}
+
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationKind {
+    PluralOf,   // a directed edge: this word is the plural of the target
+    AltForms,   // an undirected cluster: this word and the targets are alternative forms
+}
+
+
+// One template's extraction rule
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    pub relation: RelationKind,
+    pub language_arg: ArgRef,
+
+    // The target word(s). Ignored when `delimiter` is set, in which case
+    // targets are instead every positional argument after the first (the
+    // language code) up to the delimiter
+    // (mirroring `{{alter}}`'s own "forms | delimiter | dialects" layout).
+    #[serde(default)]
+    pub word_args: Vec<ArgRef>,
+    #[serde(default)]
+    pub delimiter: Option<String>,
+
+    // Skip the template if any of its dialect/qualifier arguments (the
+    // positional arguments past `delimiter`) match this regex; without a
+    // `delimiter`, the target words themselves are matched against it
+    #[serde(default)]
+    pub skip_if: Option<String>,
+
+    // Restrict the rule to templates found under this (ordinal-stripped)
+    // part-of-speech heading, e.g. "noun" for `{{plural of}}`
+    #[serde(default)]
+    pub pos: Option<String>,
+}
+
+
+// A full, loadable rule set
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub language: String,      // the Wiktionary language header to extract, e.g. "english"
+    pub language_tag: String,  // the template language-code argument to match, e.g. "en"
+    pub templates: HashMap<String, Rule>,
+}
+
+
+impl Config {
+    pub fn load(path: &str) -> Config {
+        let file = File::open(path).unwrap_or_else(|e| panic!("cannot open {}: {}", path, e));
+        serde_json::from_reader(file).unwrap_or_else(|e| panic!("cannot parse {}: {}", path, e))
+    }
+
+    // The rule set this crate shipped with before extraction rules became configurable
+    pub fn default_english() -> Config {
+        let mut templates = HashMap::new();
+        templates.insert("plural of".into(), Rule {
+            relation: RelationKind::PluralOf,
+            language_arg: ArgRef::Positional(1),
+            word_args: vec![ArgRef::Positional(2)],
+            delimiter: None,
+            skip_if: None,
+            pos: Some("noun".into()),
+        });
+        let alt_forms_rule = || Rule {
+            relation: RelationKind::AltForms,
+            language_arg: ArgRef::Positional(1),
+            word_args: vec![ArgRef::Positional(2)],
+            delimiter: None,
+            skip_if: None,
+            pos: None,
+        };
+        for name in [
+            "standard spelling of", "alternative spelling of", "standard form of",
+            "alternative form of", "stand sp", "alt sp", "alt spelling", "alt form", "altform",
+        ] {
+            templates.insert(name.into(), alt_forms_rule());
+        }
+        templates.insert("alter".into(), Rule {
+            relation: RelationKind::AltForms,
+            language_arg: ArgRef::Positional(1),
+            word_args: Vec::new(),
+            delimiter: Some(String::new()),
+            skip_if: Some(r"\b(?:obsolete|archaic|dated|rare)\b".into()),
+            pos: None,
+        });
+        Config {
+            language: "english".into(),
+            language_tag: "en".into(),
+            templates,
+        }
+    }
+
+    pub fn compile(self) -> CompiledConfig {
+        let header = Regex::new(&format!(
+            r"(?ism)(?:^== *{} *== *\n)(.*?)(?:^={{1,2}}[^=]|\z)",
+            regex::escape(&self.language),
+        )).unwrap();
+        let templates = self.templates.into_iter()
+            .map(|(name, rule)| (name, rule.compile()))
+            .collect();
+        CompiledConfig {
+            language_header: header,
+            language_tag: self.language_tag,
+            templates,
+        }
+    }
+}
+
+
+impl Rule {
+    fn compile(self) -> CompiledRule {
+        CompiledRule {
+            relation: self.relation,
+            language_arg: self.language_arg,
+            word_args: self.word_args,
+            delimiter: self.delimiter,
+            skip_if: self.skip_if.map(|pattern| Regex::new(&pattern).unwrap()),
+            pos: self.pos,
+        }
+    }
+}
+
+
+pub struct CompiledRule {
+    pub relation: RelationKind,
+    pub language_arg: ArgRef,
+    pub word_args: Vec<ArgRef>,
+    pub delimiter: Option<String>,
+    pub skip_if: Option<Regex>,
+    pub pos: Option<String>,
+}
+
+
+pub struct CompiledConfig {
+    pub language_header: Regex,
+    pub language_tag: String,
+    pub templates: HashMap<String, CompiledRule>,
+}
+
+
+// Resolve one argument reference against a template's decoded arguments
+pub fn resolve_arg<'a>(
+    aref: &ArgRef,
+    args: &[Cow<'a, str>],
+    kwargs: &HashMap<&'a str, Cow<'a, str>>,
+) -> Option<Cow<'a, str>> {
+    match aref {
+        ArgRef::Positional(n) => n.checked_sub(1).and_then(|i| args.get(i)).cloned(),
+        ArgRef::Named(name) => kwargs.get(name.as_str()).cloned(),
+    }
+}
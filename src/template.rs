@@ -1,51 +1,187 @@
 // Copyright (c) 2021, Andrey Dubovik <andrei@dubovik.eu>
 
 // A rudimentary parser for Mediawiki templates
+//
+// Templates are parsed once into a flat, position-indexed arena (`Buf`):
+// every `{{...}}` encountered, however deeply nested, becomes its own
+// `Template` entry, and an argument's value is a sequence of terms that
+// are either a raw text span or a reference to a nested template. This
+// lets callers read through nested templates (e.g. a form wrapped in a
+// qualifier) instead of seeing the literal, un-expanded `{{...}}` text.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 
+use phf::phf_set;
 
-// Iterate over (possibly nested) mediawiki templates
-// TODO: currently, nested templates and nowiki markup are left as is;
-// in principle, these can be expanded
-fn process_templates_inner<F>(text: &str, i: usize, func: &mut F) -> usize
-    where F: FnMut(&str, &[(Option<&str>, &str)]) -> ()
-{
+
+// A byte span into the original text
+type Span = (usize, usize);
+
+
+// One term of an argument's value
+#[derive(Debug)]
+enum Term {
+    Text(Span),
+    Param(Span),      // the raw contents of a `{{{...}}}` parameter reference
+    Nested(TemplateId),
+}
+
+
+#[derive(Debug)]
+struct Argument {
+    name: Option<Span>,
+    value: Vec<Term>,
+}
+
+
+#[derive(Debug)]
+pub struct Template {
+    name: Span,
+    args: Vec<Argument>,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateId(usize);
+
+
+// The arena produced by parsing a page (or section) of wikitext
+pub struct Buf<'a> {
+    text: &'a str,
+    templates: Vec<Template>,
+}
+
+
+impl<'a> Buf<'a> {
+    // Iterate over every template in the arena, in the order it was closed
+    // (nested templates are yielded before the template that contains them,
+    // matching the order in which a caller would want to resolve them)
+    pub fn templates<'b>(&'b self) -> impl Iterator<Item = TemplateHandle<'b, 'a>> {
+        self.templates.iter().map(move |template| TemplateHandle { buf: self, template })
+    }
+}
+
+
+// A resolved view of one template call
+pub struct TemplateHandle<'b, 'a> {
+    buf: &'b Buf<'a>,
+    template: &'b Template,
+}
+
+
+impl<'b, 'a> TemplateHandle<'b, 'a> {
+    pub fn name(&self) -> &'a str {
+        let (i, j) = self.template.name;
+        self.buf.text[i..j].trim()
+    }
+
+    // Render each argument's value, expanding nested templates as we go
+    pub fn args(&self) -> Vec<(Option<&'a str>, Cow<'a, str>)> {
+        self.template.args.iter()
+            .map(|arg| (arg.name.map(|(i, j)| self.buf.text[i..j].trim()), self.render(&arg.value)))
+            .collect()
+    }
+
+    fn render(&self, value: &[Term]) -> Cow<'a, str> {
+        if let [Term::Text((i, j))] = value {
+            return Cow::Borrowed(self.buf.text[*i..*j].trim());
+        }
+        let mut buf = String::new();
+        for term in value {
+            match term {
+                Term::Text((i, j)) => buf.push_str(&self.buf.text[*i..*j]),
+                Term::Param((i, j)) => buf.push_str(&self.buf.text[*i..*j]),
+                Term::Nested(id) => {
+                    let nested = TemplateHandle { buf: self.buf, template: &self.buf.templates[id.0] };
+                    buf.push_str(&nested.expand());
+                },
+            }
+        }
+        Cow::Owned(buf.trim().to_string())
+    }
+
+    // A best-effort textual expansion of a nested template: its positional
+    // arguments joined together. Known link templates (`{{l|en|foo}}`,
+    // `{{m|en|foo}}`) lead with a language code, which is skipped; other
+    // templates, e.g. qualifier wrappers like `{{q|obsolete}}`, carry their
+    // text starting in the first positional, so it's kept
+    fn expand(&self) -> String {
+        let skip = if LINK_TEMPLATES.contains(self.name()) { 1 } else { 0 };
+        self.args().into_iter()
+            .filter(|(name, _)| name.is_none())
+            .map(|(_, value)| value.into_owned())
+            .skip(skip)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+
+// Templates that name a word or phrase in a given language, leading with a
+// language-code positional argument ahead of the term itself
+static LINK_TEMPLATES: phf::Set<&'static str> = phf_set! {
+    "l", "link", "m", "m+", "mention",
+};
+
+
+// Parse one template call starting just past its opening `{{`, recursing into
+// any nested template calls. Returns the byte offset into `text` one past the closing
+// `}}`, along with the new template's id; called once at the top level with
+// `i = 0` to parse a whole page, in which case there is no closing `}}` and
+// the "template" collected there (and its id) is simply discarded.
+fn parse_template(text: &str, i: usize, templates: &mut Vec<Template>) -> (usize, Option<TemplateId>) {
     let mut i = i;
     let bytes = text.as_bytes();
     let len = bytes.len();
-    let mut args = Vec::new();
+    let mut args: Vec<Argument> = Vec::new();
+    let mut value: Vec<Term> = Vec::new();
     let mut start = i;
     let mut argname = None;
     while i < len {
         if (bytes[i] as i8) >= -0x40 {  // Unicode boundary
             // Collect argument name
-            if bytes [i] == b'=' && argname.is_none() {
-                argname = Some(&text[start..i]);
+            if bytes[i] == b'=' && argname.is_none() {
+                argname = Some((start, i));
                 i += 1;
                 start = i;
             }
             // Collect argument
             else if bytes[i] == b'|' {
-                args.push((argname, &text[start..i]));
+                value.push(Term::Text((start, i)));
+                args.push(Argument { name: argname.take(), value: std::mem::take(&mut value) });
                 i += 1;
                 start = i;
-                argname = None;
             }
-            // "{{{" should not occur
+            // Triple-brace parameter reference, e.g. `{{{1|default}}}`
             else if i + 2 < len && &bytes[i..i+3] == b"{{{" {
-                panic!("{}", "{{{ encountered")
+                let pstart = i + 3;
+                let pend = match text[pstart..].find("}}}") {
+                    Some(j) => pstart + j,
+                    None => len,
+                };
+                value.push(Term::Text((start, i)));
+                value.push(Term::Param((pstart, pend)));
+                i = (pend + 3).min(len);
+                start = i;
             }
             // Enter new template
             else if i + 1 < len && &bytes[i..i+2] == b"{{" {
-                i = process_templates_inner(text, i + 2, func);
+                value.push(Term::Text((start, i)));
+                let (next_i, id) = parse_template(text, i + 2, templates);
+                if let Some(id) = id {
+                    value.push(Term::Nested(id));
+                }
+                i = next_i;
+                start = i;
             }
             // Process template and exit
             else if i + 1 < len && &bytes[i..i+2] == b"}}" {
-                args.push((argname, &text[start..i]));
-                //args[0] = args[0].trim();  // Trim name by default
-                func(args[0].1.trim(), &args[1..]);
-                return i + 2;
+                value.push(Term::Text((start, i)));
+                args.push(Argument { name: argname.take(), value: std::mem::take(&mut value) });
+                let name = args.remove(0).name_or_text();
+                templates.push(Template { name, args });
+                return (i + 2, Some(TemplateId(templates.len() - 1)));
             }
             // Skip over <nowiki> segments
             else if i + 7 < len && &bytes[i..i+8] == b"<nowiki>" {
@@ -69,15 +205,28 @@ fn process_templates_inner<F>(text: &str, i: usize, func: &mut F) -> usize
             i += 1;
         }
     }
-    i
+    (i, None)  // ran off the end without a closing `}}`: not a template
+}
+
+
+impl Argument {
+    // The template name is always plain text in practice; in the (malformed)
+    // case where it isn't, only the text before its first nested template
+    // or parameter is used
+    fn name_or_text(self) -> Span {
+        match self.value.as_slice() {
+            [Term::Text(span), ..] => *span,
+            _ => (0, 0),
+        }
+    }
 }
 
 
-// A public wrapper for a top-level call
-pub fn process_templates<F>(text: &str, mut func: F)
-    where F: FnMut(&str, &[(Option<&str>, &str)]) -> ()
-{
-    process_templates_inner(text, 0, &mut func);
+// Parse a whole page (or section) of wikitext into an arena of templates
+pub fn process_templates(text: &str) -> Buf {
+    let mut templates = Vec::new();
+    parse_template(text, 0, &mut templates);
+    Buf { text, templates }
 }
 
 
@@ -98,12 +247,11 @@ impl<T: Clone + Default> Setter<T> for Vec<T> {
 
 
 // Destructure mediawiki template parameters
-pub fn decode_arguments<'a>(args: &'a[(Option<&str>, &str)]) -> (Vec<&'a str>, HashMap<&'a str, &'a str>) {
+pub fn decode_arguments<'a>(args: Vec<(Option<&'a str>, Cow<'a, str>)>) -> (Vec<Cow<'a, str>>, HashMap<&'a str, Cow<'a, str>>) {
     let mut nargs = Vec::new();
     let mut kwargs = HashMap::new();
     let mut i = 1;
     for (name, value) in args {
-        let value = value.trim();
         match name {
             None => {
                 nargs.set(i - 1, value);